@@ -1,12 +1,14 @@
 extern crate cargo_metadata;
 extern crate walkdir;
 
-use std::collections::hash_map::Entry;
+use std::collections::hash_map::{DefaultHasher, Entry};
 use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
 use std::time::SystemTime;
 
 use std::ffi::OsStr;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::process::Command;
@@ -24,10 +26,16 @@ error_chain! {
     }
 }
 
+// The level of verification to run a snippet through via `compile_test_phase`.
+// `Check` type-checks without codegen, and `Full` builds and (via
+// `run_test`) runs it.
+//
+// There is no syntax-only level below `Check`: stable rustc has no
+// parse-only mode, so every level runs the type checker.
 #[derive(Clone, Copy)]
-enum CompileType {
-    Full,
+pub enum CompileType {
     Check,
+    Full,
 }
 
 // An iterator over the root dependencies in a lockfile
@@ -158,8 +166,14 @@ fn get_edition<P: AsRef<Path>>(path: P) -> Result<String> {
 }
 
 // Retrieve the exact dependencies for a given build by
-// cross-referencing the lockfile with the fingerprint file
-fn get_rlib_dependencies<P: AsRef<Path>>(root_dir: P, target_dir: P) -> Result<Vec<Fingerprint>> {
+// cross-referencing the lockfile with the fingerprint file.
+// Also returns the raw lockfile-derived dependency map so callers can
+// tell a crate that is genuinely unresolvable apart from one that is
+// simply unreferenced by a given snippet.
+fn get_rlib_dependencies<P: AsRef<Path>>(
+    root_dir: P,
+    target_dir: P,
+) -> Result<(HashMap<String, String>, Vec<Fingerprint>)> {
     let root_dir = root_dir.as_ref();
     let target_dir = target_dir.as_ref();
     let lock = LockedDeps::from_path(root_dir).or_else(|_| {
@@ -201,17 +215,133 @@ fn get_rlib_dependencies<P: AsRef<Path>>(root_dir: P, target_dir: P) -> Result<V
         }
     }
 
-    Ok(found_deps
+    let found_deps = found_deps
         .into_iter()
         .filter_map(|(_, val)| if val.rlib.exists() { Some(val) } else { None })
-        .collect())
+        .collect();
+
+    Ok((locked_deps, found_deps))
+}
+
+// Identifiers that can prefix a path without naming a crate.
+const PATH_REF_KEYWORDS: &[&str] = &["crate", "self", "super", "Self", "dyn"];
+
+// Crates that are always available without an --extern flag, so a path
+// rooted in one of these is never a "missing dependency", just not one
+// of ours.
+const SYSROOT_CRATES: &[&str] = &["std", "core", "alloc", "proc_macro", "test"];
+
+fn is_ident_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+fn is_crate_root(name: &str) -> bool {
+    // Crate names are conventionally lower_snake_case; a leading segment
+    // that starts with an uppercase letter is a prelude or locally-scoped
+    // type (`Vec::new()`, `String::from`, a `use`-imported `HashMap`, ...),
+    // never a crate, so exclude it regardless of what follows.
+    let starts_uppercase = name.starts_with(char::is_uppercase);
+    !starts_uppercase && !PATH_REF_KEYWORDS.contains(&name) && !SYSROOT_CRATES.contains(&name)
+}
+
+// A lightweight scan (no real parsing) over a snippet's source for the
+// crate names it references: `extern crate foo;` declarations, the root
+// segment of `use foo::...;` imports, and the root segment of bare
+// `foo::bar()`-style paths. This mirrors how tools like rustpkg used to
+// infer a package list from `extern mod`/`use` statements, and lets us
+// pass rustc only the `--extern` flags a snippet actually needs.
+fn referenced_crates(test_text: &str) -> std::collections::HashSet<String> {
+    let mut crates = std::collections::HashSet::new();
+
+    for line in test_text.lines() {
+        let line = line.trim_start();
+        let rest = line
+            .strip_prefix("extern crate ")
+            .or_else(|| line.strip_prefix("use "));
+        if let Some(rest) = rest {
+            if let Some(name) = rest
+                .split(|c: char| !is_ident_char(c))
+                .find(|s| !s.is_empty())
+            {
+                if is_crate_root(name) {
+                    crates.insert(name.to_owned());
+                }
+            }
+        }
+    }
+
+    // Bare paths: only the leading segment of a `foo::bar::baz()` chain
+    // can be a crate root, so a segment immediately preceded by "::" is
+    // skipped — otherwise `std::collections::HashMap::new()` would also
+    // capture `collections`.
+    let chars: Vec<char> = test_text.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        if !is_ident_char(chars[i]) {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        while i < chars.len() && is_ident_char(chars[i]) {
+            i += 1;
+        }
+        let followed_by_path_sep = chars[i..].starts_with(&[':', ':']);
+        let preceded_by_path_sep = start >= 2 && chars[start - 2] == ':' && chars[start - 1] == ':';
+        if followed_by_path_sep && !preceded_by_path_sep {
+            let word: String = chars[start..i].iter().collect();
+            if is_crate_root(&word) {
+                crates.insert(word);
+            }
+        }
+    }
+
+    crates
 }
 
 fn temp_dir(prefix: &str) -> tempfile::TempDir {
     tempfile::Builder::new().prefix(prefix).tempdir().unwrap()
 }
 
+// Build the Command used to invoke rustc, running it through
+// RUSTC_WRAPPER, or through RUSTC_WORKSPACE_WRAPPER when RUSTC_WRAPPER is
+// unset, so tools like sccache can transparently cache the compilation of
+// doc-test snippets. At most one wrapper is applied, and an empty value
+// means no wrapper.
+fn rustc_command(rustc: &str) -> Command {
+    let wrapper = env::var("RUSTC_WRAPPER")
+        .or_else(|_| env::var("RUSTC_WORKSPACE_WRAPPER"))
+        .ok()
+        .filter(|wrapper| !wrapper.is_empty());
+
+    match wrapper {
+        Some(wrapper) => {
+            let mut cmd = Command::new(wrapper);
+            cmd.arg(rustc);
+            cmd
+        }
+        None => Command::new(rustc),
+    }
+}
+
 pub fn compile_test(root_dir: &str, out_dir: &str, target_triple: &str, test_text: &str) {
+    compile_test_phase(
+        root_dir,
+        out_dir,
+        target_triple,
+        test_text,
+        CompileType::Check,
+    );
+}
+
+// Like `compile_test`, but lets the caller pick how thorough the
+// verification needs to be, trading speed for the class of errors caught.
+pub fn compile_test_phase(
+    root_dir: &str,
+    out_dir: &str,
+    target_triple: &str,
+    test_text: &str,
+    phase: CompileType,
+) {
     let rustc = &env::var("RUSTC").unwrap_or_else(|_| String::from("rustc"));
     let outdir = &temp_dir("rust-skeptic");
     let testcase_path = &outdir.path().join("test.rs");
@@ -225,7 +355,8 @@ pub fn compile_test(root_dir: &str, out_dir: &str, target_triple: &str, test_tex
         root_dir,
         out_dir,
         target_triple,
-        CompileType::Check,
+        test_text,
+        phase,
     );
 }
 
@@ -243,6 +374,7 @@ pub fn run_test(root_dir: &str, out_dir: &str, target_triple: &str, test_text: &
         root_dir,
         out_dir,
         target_triple,
+        test_text,
         CompileType::Full,
     );
     run_test_case(binary_path, outdir.path());
@@ -260,8 +392,36 @@ fn compile_test_case(
     root_dir: &str,
     out_dir: &str,
     target_triple: &str,
+    test_text: &str,
     compile_type: CompileType,
 ) {
+    let ctx = BuildContext::resolve(root_dir, out_dir, rustc);
+    if let Err(msg) = ctx.compile(
+        in_path,
+        out_path,
+        rustc,
+        target_triple,
+        test_text,
+        compile_type,
+    ) {
+        panic!("{}", msg);
+    }
+}
+
+// Everything needed to turn a snippet into a rustc invocation (reading
+// Cargo.toml, walking .fingerprint/, and asking rustc its own version are
+// not free). `run_tests` resolves one and reuses it for every snippet in
+// the batch; `compile_test_case` resolves a fresh one per snippet.
+struct BuildContext {
+    edition: String,
+    locked_deps: HashMap<String, String>,
+    deps: Vec<Fingerprint>,
+    target_dir: PathBuf,
+    deps_dir: PathBuf,
+    rustc_version: String,
+}
+
+impl BuildContext {
     // OK, here's where a bunch of magic happens using assumptions
     // about cargo internals. We are going to use rustc to compile
     // the examples, but to do that we've got to tell it where to
@@ -269,49 +429,273 @@ fn compile_test_case(
     // are with the --extern flag. This is going to involve
     // parsing fingerprints out of the lockfile and looking them
     // up in the fingerprint file.
+    fn resolve(root_dir: &str, out_dir: &str, rustc: &str) -> BuildContext {
+        let root_dir = PathBuf::from(root_dir);
+        let mut target_dir = PathBuf::from(out_dir);
+        target_dir.pop();
+        target_dir.pop();
+        target_dir.pop();
+        let deps_dir = target_dir.join("deps");
+
+        let edition = get_edition(&root_dir).expect("failed to read Cargo.toml");
+        let (locked_deps, deps) = get_rlib_dependencies(root_dir, target_dir.clone())
+            .expect("failed to read dependencies");
+        let rustc_version = rustc_version_string(rustc);
+
+        BuildContext {
+            edition,
+            locked_deps,
+            deps,
+            target_dir,
+            deps_dir,
+            rustc_version,
+        }
+    }
+
+    fn compile(
+        &self,
+        in_path: &Path,
+        out_path: &Path,
+        rustc: &str,
+        target_triple: &str,
+        test_text: &str,
+        compile_type: CompileType,
+    ) -> std::result::Result<(), String> {
+        // Only the crates a snippet actually names need to be passed to
+        // rustc with --extern; this keeps the command line small and
+        // avoids name collisions among unrelated resolved dependencies.
+        let referenced = referenced_crates(test_text);
+        for name in &referenced {
+            if !self.locked_deps.contains_key(name) {
+                eprintln!(
+                    "skeptic: snippet references crate `{}`, which is not a dependency of this crate",
+                    name
+                );
+            }
+        }
+        let deps: Vec<&Fingerprint> = self
+            .deps
+            .iter()
+            .filter(|dep| referenced.contains(&dep.libname))
+            .collect();
+
+        let cache_key = compile_cache_key(
+            test_text,
+            &self.edition,
+            target_triple,
+            &self.rustc_version,
+            &deps,
+        );
+        let cache_dir = self.target_dir.join("skeptic-cache").join(cache_key);
+        if restore_from_cache(&cache_dir, out_path, compile_type) {
+            return Ok(());
+        }
+
+        let mut cmd = rustc_command(rustc);
+        cmd.arg(in_path).arg("--verbose").arg("--crate-type=bin");
+
+        // This has to come before "-L".
+        if self.edition != "2015" {
+            cmd.arg(format!("--edition={}", self.edition));
+        }
 
-    let root_dir = PathBuf::from(root_dir);
-    let mut target_dir = PathBuf::from(out_dir);
-    target_dir.pop();
-    target_dir.pop();
-    target_dir.pop();
-    let mut deps_dir = target_dir.clone();
-    deps_dir.push("deps");
-
-    let mut cmd = Command::new(rustc);
-    cmd.arg(in_path).arg("--verbose").arg("--crate-type=bin");
-
-    // This has to come before "-L".
-    let edition = get_edition(&root_dir).expect("failed to read Cargo.toml");
-    if edition != "2015" {
-        cmd.arg(format!("--edition={}", edition));
+        cmd.arg("-L")
+            .arg(&self.target_dir)
+            .arg("-L")
+            .arg(&self.deps_dir)
+            .arg("--target")
+            .arg(target_triple);
+
+        for dep in &deps {
+            cmd.arg("--extern");
+            cmd.arg(format!(
+                "{}={}",
+                dep.libname,
+                dep.rlib.to_str().expect("filename not utf8"),
+            ));
+        }
+
+        match compile_type {
+            CompileType::Full => cmd.arg("-o").arg(out_path),
+            CompileType::Check => cmd.arg(format!(
+                "--emit=dep-info={0}.d,metadata={0}.m",
+                out_path.display()
+            )),
+        };
+
+        run_command(&mut cmd)?;
+        save_to_cache(&cache_dir, out_path, compile_type);
+        Ok(())
     }
+}
 
-    cmd.arg("-L")
-        .arg(&target_dir)
-        .arg("-L")
-        .arg(&deps_dir)
-        .arg("--target")
-        .arg(&target_triple);
-
-    for dep in get_rlib_dependencies(root_dir, target_dir).expect("failed to read dependencies") {
-        cmd.arg("--extern");
-        cmd.arg(format!(
-            "{}={}",
-            dep.libname,
-            dep.rlib.to_str().expect("filename not utf8"),
-        ));
+// Compile and run many snippets from the same crate concurrently. The
+// dependency resolution that `compile_test_case` otherwise repeats per
+// snippet (reading Cargo.toml, walking .fingerprint/) happens exactly
+// once here and is shared across a bounded pool of worker threads, one
+// rustc (or test binary) process in flight per worker at a time.
+// Failures are collected rather than raised immediately, so a single bad
+// snippet doesn't hide failures in the rest of the batch; the aggregated
+// failures are reported together in one panic.
+pub fn run_tests(root_dir: &str, target_triple: &str, tests: &[(&str, &str)]) {
+    if tests.is_empty() {
+        return;
+    }
+
+    let rustc = &env::var("RUSTC").unwrap_or_else(|_| String::from("rustc"));
+    let (_, first_out_dir) = tests[0];
+    let ctx = &BuildContext::resolve(root_dir, first_out_dir, rustc);
+
+    let worker_count = std::thread::available_parallelism()
+        .map(std::num::NonZeroUsize::get)
+        .unwrap_or(1)
+        .min(tests.len());
+    let remaining = &Mutex::new(tests.iter().enumerate());
+    let failures = &Mutex::new(Vec::new());
+
+    std::thread::scope(|scope| {
+        for _ in 0..worker_count {
+            scope.spawn(move || loop {
+                let job = remaining.lock().unwrap().next();
+                let (index, &(test_text, _out_dir)) = match job {
+                    Some(job) => job,
+                    None => return,
+                };
+
+                if let Err(msg) = compile_and_run_one(ctx, rustc, target_triple, test_text) {
+                    failures
+                        .lock()
+                        .unwrap()
+                        .push(format!("test #{}: {}", index, msg));
+                }
+            });
+        }
+    });
+
+    let failures = failures.lock().unwrap();
+    if !failures.is_empty() {
+        panic!(
+            "{} of {} doc-test snippet(s) failed:\n\n{}",
+            failures.len(),
+            tests.len(),
+            failures.join("\n\n")
+        );
     }
+}
 
+fn compile_and_run_one(
+    ctx: &BuildContext,
+    rustc: &str,
+    target_triple: &str,
+    test_text: &str,
+) -> std::result::Result<(), String> {
+    let outdir = temp_dir("rust-skeptic");
+    let testcase_path = outdir.path().join("test.rs");
+    let binary_path = outdir.path().join("out.exe");
+
+    write_test_case(&testcase_path, test_text);
+    ctx.compile(
+        &testcase_path,
+        &binary_path,
+        rustc,
+        target_triple,
+        test_text,
+        CompileType::Full,
+    )?;
+    run_command_case(&binary_path, outdir.path())
+}
+
+// Digest the inputs that fully determine the result of compiling a
+// snippet: the snippet text itself, the edition and target it's compiled
+// for, the exact rustc in use, and the dependency rlibs it will be linked
+// against. Folding each dependency's mtime into the digest (rather than
+// just its path) is the critical invariant here: cargo rebuilds rlibs in
+// place at the same path, so a hash that ignored mtime would happily
+// serve a stale cache hit against a dependency that has since changed.
+fn compile_cache_key(
+    test_text: &str,
+    edition: &str,
+    target_triple: &str,
+    rustc_version: &str,
+    deps: &[&Fingerprint],
+) -> String {
+    let mut hasher = DefaultHasher::new();
+    test_text.hash(&mut hasher);
+    edition.hash(&mut hasher);
+    target_triple.hash(&mut hasher);
+    rustc_version.hash(&mut hasher);
+
+    let mut deps: Vec<&&Fingerprint> = deps.iter().collect();
+    deps.sort_by(|a, b| (&a.libname, &a.rlib).cmp(&(&b.libname, &b.rlib)));
+    for dep in deps {
+        dep.libname.hash(&mut hasher);
+        dep.version.hash(&mut hasher);
+        dep.rlib.hash(&mut hasher);
+        dep.mtime.hash(&mut hasher);
+    }
+
+    format!("{:016x}", hasher.finish())
+}
+
+fn rustc_version_string(rustc: &str) -> String {
+    let output = rustc_command(rustc)
+        .arg("--version")
+        .arg("--verbose")
+        .output()
+        .expect("failed to run rustc --version --verbose");
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+fn restore_from_cache(cache_dir: &Path, out_path: &Path, compile_type: CompileType) -> bool {
     match compile_type {
-        CompileType::Full => cmd.arg("-o").arg(out_path),
-        CompileType::Check => cmd.arg(format!(
-            "--emit=dep-info={0}.d,metadata={0}.m",
-            out_path.display()
-        )),
-    };
+        CompileType::Full => {
+            let cached_bin = cache_dir.join("bin");
+            cached_bin.exists() && fs::copy(&cached_bin, out_path).is_ok()
+        }
+        CompileType::Check => {
+            let cached_m = cache_dir.join("meta.m");
+            let cached_d = cache_dir.join("meta.d");
+            cached_m.exists()
+                && cached_d.exists()
+                && fs::copy(&cached_m, format!("{}.m", out_path.display())).is_ok()
+                && fs::copy(&cached_d, format!("{}.d", out_path.display())).is_ok()
+        }
+    }
+}
 
-    interpret_output(cmd);
+// Populate `dst` from `src` without ever exposing a partially-written
+// file at `dst`: concurrent workers can race to populate the same
+// cache entry for duplicate snippets, and a bare `fs::copy` into the
+// final path would let one thread's `restore_from_cache` read another
+// thread's in-progress write. Writing to a sibling temp file first and
+// renaming it into place is atomic on the same filesystem, so readers
+// only ever see the old or the fully-written new content.
+fn copy_into_cache_atomically(src: &Path, dst: &Path) {
+    let dir = dst.parent().expect("cache file must have a parent dir");
+    let tmp =
+        tempfile::NamedTempFile::new_in(dir).expect("failed to create compile cache tempfile");
+    fs::copy(src, tmp.path()).expect("failed to populate compile cache");
+    tmp.persist(dst)
+        .expect("failed to finalize compile cache entry");
+}
+
+fn save_to_cache(cache_dir: &Path, out_path: &Path, compile_type: CompileType) {
+    fs::create_dir_all(cache_dir).expect("failed to create skeptic compile cache dir");
+    match compile_type {
+        CompileType::Full => {
+            copy_into_cache_atomically(out_path, &cache_dir.join("bin"));
+        }
+        CompileType::Check => {
+            copy_into_cache_atomically(
+                &PathBuf::from(format!("{}.m", out_path.display())),
+                &cache_dir.join("meta.m"),
+            );
+            copy_into_cache_atomically(
+                &PathBuf::from(format!("{}.d", out_path.display())),
+                &cache_dir.join("meta.d"),
+            );
+        }
+    }
 }
 
 fn run_test_case(program_path: &Path, outdir: &Path) {
@@ -320,11 +704,70 @@ fn run_test_case(program_path: &Path, outdir: &Path) {
     interpret_output(cmd);
 }
 
+fn run_command_case(program_path: &Path, outdir: &Path) -> std::result::Result<(), String> {
+    let mut cmd = Command::new(program_path);
+    cmd.current_dir(outdir);
+    run_command(&mut cmd)
+}
+
 fn interpret_output(mut command: Command) {
+    if let Err(msg) = run_command(&mut command) {
+        panic!("{}", msg);
+    }
+}
+
+fn run_command(command: &mut Command) -> std::result::Result<(), String> {
     let output = command.output().unwrap();
     print!("{}", String::from_utf8(output.stdout).unwrap());
     eprint!("{}", String::from_utf8(output.stderr).unwrap());
     if !output.status.success() {
-        panic!("Command failed:\n{:?}", command);
+        return Err(format!("Command failed:\n{:?}", command));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::referenced_crates;
+
+    fn refs(text: &str) -> Vec<String> {
+        let mut refs: Vec<String> = referenced_crates(text).into_iter().collect();
+        refs.sort();
+        refs
+    }
+
+    #[test]
+    fn extern_crate_and_use_declarations() {
+        assert_eq!(refs("extern crate foo;"), vec!["foo"]);
+        assert_eq!(refs("use foo::Bar;"), vec!["foo"]);
+    }
+
+    #[test]
+    fn only_the_leading_segment_of_a_path_counts() {
+        assert_eq!(refs("foo::bar::baz()"), vec!["foo"]);
+        assert_eq!(refs("crate::foo::Bar::baz()"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn sysroot_paths_are_not_crate_references() {
+        assert_eq!(
+            refs("std::collections::HashMap::new()"),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            refs("let v: alloc::vec::Vec<u8> = alloc::vec::Vec::new();"),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn bare_prelude_and_imported_types_are_not_crate_references() {
+        assert_eq!(refs("Vec::new()"), Vec::<String>::new());
+        assert_eq!(refs(r#"String::from("x")"#), Vec::<String>::new());
+        assert_eq!(refs("Box::new(5)"), Vec::<String>::new());
+        assert_eq!(
+            refs("use std::collections::HashMap;\nHashMap::new()"),
+            Vec::<String>::new()
+        );
     }
 }